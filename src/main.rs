@@ -1,30 +1,135 @@
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
-use bson::Document;
+use bson::{doc, Bson, Document};
 use structopt::clap;
 use structopt::StructOpt;
 
 static AUTO_FLUSH: i64 = 100_000;
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "bsonsplit", about = "Splits a BSON file")]
-struct Cli {
-    /// The maximum amount of resulting files. Must be at least 1.
+#[structopt(name = "bsonsplit", about = "Splits and merges BSON files")]
+enum Cli {
+    /// Split a BSON file into multiple shards.
+    Split(SplitOpts),
+    /// Concatenate shard files back into a single BSON stream.
+    Merge(MergeOpts),
+}
+
+#[derive(Debug, StructOpt)]
+struct SplitOpts {
+    /// The maximum amount of resulting files. Must be at least 1. In the default
+    /// round-robin mode this is the exact number of files; with --max-size-mb or
+    /// --max-docs it is an optional upper bound on the total number of files.
     #[structopt(short, long)]
-    split: u32,
-    /// The path to the file to read
+    split: Option<u32>,
+    /// Start a new output file whenever the current shard would exceed this many
+    /// megabytes. Files are created lazily as documents are written.
+    #[structopt(long)]
+    max_size_mb: Option<u64>,
+    /// Start a new output file after this many documents have been written to the
+    /// current shard. Files are created lazily as documents are written.
+    #[structopt(long)]
+    max_docs: Option<u64>,
+    /// Route documents by a field instead of round-robin: all documents sharing
+    /// the same value for this field land in the same output file. Supports
+    /// dotted paths like `a.b.c`.
+    #[structopt(long)]
+    shard_by: Option<String>,
+    /// Output file index (0-based) for documents missing the --shard-by field.
+    #[structopt(long, default_value = "0")]
+    fallback_bucket: usize,
+    /// Output name base for generated file names. Required when reading from
+    /// stdin without --stdout; otherwise defaults to the input file's stem.
+    #[structopt(long)]
+    prefix: Option<String>,
+    /// Write the selected documents to stdout as a single stream instead of
+    /// numbered files (typically combined with --split 1).
+    #[structopt(long)]
+    stdout: bool,
+    /// The path to the file to read. Use `-` or omit it to read from stdin.
     #[structopt(parse(from_os_str))]
-    path: std::path::PathBuf,
+    path: Option<std::path::PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct MergeOpts {
+    /// Output file; writes to stdout if omitted.
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<std::path::PathBuf>,
+    /// Input shard files to concatenate, in order. Each argument may be a path or
+    /// a glob (e.g. `dump-*.bson`).
+    #[structopt(parse(from_os_str), required = true)]
+    inputs: Vec<std::path::PathBuf>,
 }
 
-fn validate(opt: &Cli) {
-    if opt.split < 1 {
-        clap::Error::with_description("split must be at least 1", clap::ErrorKind::InvalidValue)
+fn validate(opt: &SplitOpts) {
+    if let Some(split) = opt.split {
+        if split < 1 {
+            clap::Error::with_description(
+                "split must be at least 1",
+                clap::ErrorKind::InvalidValue,
+            )
             .exit();
+        }
+    }
+    if opt.max_size_mb.is_some() && opt.max_docs.is_some() {
+        clap::Error::with_description(
+            "--max-size-mb and --max-docs are mutually exclusive",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
     }
+    if opt.split.is_none() && opt.max_size_mb.is_none() && opt.max_docs.is_none() {
+        clap::Error::with_description(
+            "one of --split, --max-size-mb or --max-docs is required",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit();
+    }
+    if opt.shard_by.is_some() && (opt.max_size_mb.is_some() || opt.max_docs.is_some()) {
+        clap::Error::with_description(
+            "--shard-by cannot be combined with --max-size-mb or --max-docs",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    if let (Some(_), Some(split)) = (&opt.shard_by, opt.split) {
+        if opt.fallback_bucket >= split as usize {
+            clap::Error::with_description(
+                "fallback-bucket must be less than split",
+                clap::ErrorKind::InvalidValue,
+            )
+            .exit();
+        }
+    }
+}
+
+/// Looks up a possibly-dotted field path within a document, descending through
+/// embedded documents. Returns `None` if any segment is missing.
+fn extract_field<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut parts = path.split('.');
+    let mut current = doc.get(parts.next()?)?;
+    for part in parts {
+        match current {
+            Bson::Document(d) => current = d.get(part)?,
+            _ => return None,
+        }
+    }
+    Some(current)
+}
+
+/// Stable 64-bit FNV-1a hash, used to route documents to a shard deterministically
+/// across runs (unlike `DefaultHasher`, which is not guaranteed stable).
+fn fnv1a_64(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
 }
 
 fn process_doc(doc: bson::de::Result<Document>) -> anyhow::Result<Option<Document>> {
@@ -43,73 +148,317 @@ fn process_doc(doc: bson::de::Result<Document>) -> anyhow::Result<Option<Documen
     }
 }
 
-fn create_files(prefix: &str, split: u32) -> anyhow::Result<(Vec<File>, Vec<String>)> {
-    let runtime = SystemTime::now()
+fn runtime_millis() -> anyhow::Result<u128> {
+    Ok(SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .with_context(|| "Failed to get time")?
-        .as_millis();
+        .as_millis())
+}
+
+fn output_filename(prefix: &str, runtime: u128, i: u32) -> String {
+    format!("{}-{}-{}.bson", prefix, runtime, i)
+}
+
+fn create_files(prefix: &str, split: u32) -> anyhow::Result<(Vec<File>, Vec<String>)> {
+    let runtime = runtime_millis()?;
 
     let mut files = Vec::new();
     let mut paths = Vec::new();
     for i in 1..=split {
-        let filename = format!("{}-{}-{}.bson", prefix, runtime, i);
+        let filename = output_filename(prefix, runtime, i);
         paths.push(filename.clone());
-        let f = File::create(filename)?;
+        let f = File::create(&filename)?;
         files.push(f);
     }
     Ok((files, paths))
 }
 
-fn flush_all(bufs: &mut Vec<BufWriter<&File>>) -> anyhow::Result<()> {
-    for x in bufs {
-        x.flush().with_context(|| "Failed to flush")?;
+/// Bound on the number of documents queued per shard before the parsing thread
+/// blocks, keeping memory use bounded on fast producers / slow disks.
+static QUEUE_DEPTH: usize = 1024;
+
+/// Splits the input into a fixed number of files. Documents are round-robined
+/// across the files, unless `shard_by` is set, in which case each document is
+/// routed by a stable hash of the named field so same-key records co-locate.
+///
+/// The parsing thread (this function) only reads and routes documents, handing
+/// each to a bounded per-shard channel. One writer thread per output file owns
+/// its `BufWriter` and flushes on its own cadence, so one shard's flush does not
+/// hold up writes to the others. When a shard's queue is full, the parsing
+/// thread blocks until that shard's writer catches up.
+fn split_round_robin<R: std::io::Read>(
+    mut f: R,
+    prefix: &str,
+    split: u32,
+    shard_by: Option<&str>,
+    fallback_bucket: usize,
+) -> anyhow::Result<Vec<String>> {
+    let (files, paths) =
+        create_files(prefix, split).with_context(|| "Failed to create output files")?;
+
+    let mut senders = Vec::with_capacity(files.len());
+    let mut handles = Vec::with_capacity(files.len());
+    for file in files {
+        let (tx, rx) = std::sync::mpsc::sync_channel::<Document>(QUEUE_DEPTH);
+        senders.push(tx);
+        handles.push(std::thread::spawn(move || -> anyhow::Result<()> {
+            let mut buf = BufWriter::new(file);
+            let mut writes: i64 = 0;
+            for doc in rx {
+                doc.to_writer(&mut buf)
+                    .with_context(|| "Failed to write document")?;
+                writes += 1;
+                if writes % AUTO_FLUSH == 0 {
+                    buf.flush().with_context(|| "Failed to flush")?;
+                }
+            }
+            buf.flush().with_context(|| "Failed to flush")?;
+            Ok(())
+        }));
     }
-    Ok(())
+
+    let mut cycle = (0..senders.len()).cycle();
+    loop {
+        if let Some(doc) = process_doc(Document::from_reader(&mut f))? {
+            let file_index = match shard_by {
+                Some(field) => match extract_field(&doc, field) {
+                    Some(value) => {
+                        let mut bytes = Vec::new();
+                        doc! { "v": value.clone() }
+                            .to_writer(&mut bytes)
+                            .with_context(|| "Failed to serialize shard key")?;
+                        (fnv1a_64(&bytes) % senders.len() as u64) as usize
+                    }
+                    None => fallback_bucket,
+                },
+                None => cycle.next().unwrap(),
+            };
+            senders[file_index]
+                .send(doc)
+                .with_context(|| "Writer thread stopped unexpectedly")?;
+        } else {
+            break;
+        }
+    }
+
+    // Close the channels so the writer threads drain and exit, then surface the
+    // first per-shard error (or panic) back to the caller.
+    drop(senders);
+    for handle in handles {
+        handle
+            .join()
+            .map_err(|_| anyhow::Error::msg("Writer thread panicked"))??;
+    }
+
+    Ok(paths)
 }
 
-fn main() -> anyhow::Result<()> {
-    let opt = Cli::from_args();
-    validate(&opt);
+/// Splits the input into lazily-created files, rolling over to a new file once
+/// the current shard reaches a size or document-count threshold. `split`, when
+/// set, caps the total number of files; further rollovers keep appending to the
+/// last file.
+fn split_rotating<R: std::io::Read>(
+    mut f: R,
+    prefix: &str,
+    split: Option<u32>,
+    max_bytes: Option<u64>,
+    max_docs: Option<u64>,
+) -> anyhow::Result<Vec<String>> {
+    let runtime = runtime_millis()?;
+
+    let mut paths = Vec::new();
+    let mut current: Option<BufWriter<File>> = None;
+    let mut current_bytes: u64 = 0;
+    let mut current_docs: u64 = 0;
+    let mut created: u32 = 0;
 
-    let f = File::open(opt.path.clone()).with_context(|| "Failed to open file")?;
-    let mut f = BufReader::new(f);
+    loop {
+        let doc = match process_doc(Document::from_reader(&mut f))? {
+            Some(doc) => doc,
+            None => break,
+        };
 
-    let prefix = opt
-        .path
-        .file_stem()
-        .ok_or_else(|| anyhow::Error::msg("Unable to extract prefix"))?
-        .to_str()
-        .ok_or_else(|| anyhow::Error::msg("Unable to read file path"))?;
+        let mut bytes = Vec::new();
+        doc.to_writer(&mut bytes)
+            .with_context(|| "Failed to serialize document")?;
+        let doc_len = bytes.len() as u64;
+
+        let threshold_reached = current.is_some()
+            && (max_bytes.map_or(false, |max| current_bytes + doc_len > max)
+                || max_docs.map_or(false, |max| current_docs >= max));
+        // A fresh rollover is only permitted while we are under the optional file cap.
+        let may_create = split.map_or(true, |max| created < max);
+
+        if current.is_none() || (threshold_reached && may_create) {
+            if let Some(mut w) = current.take() {
+                w.flush().with_context(|| "Failed to flush")?;
+            }
+            created += 1;
+            let filename = output_filename(prefix, runtime, created);
+            let f = File::create(&filename).with_context(|| "Failed to create output file")?;
+            paths.push(filename);
+            current = Some(BufWriter::new(f));
+            current_bytes = 0;
+            current_docs = 0;
+        }
 
-    let (output, paths) =
-        create_files(prefix, opt.split).with_context(|| "Failed to create output files")?;
+        let w = current.as_mut().unwrap();
+        w.write_all(&bytes)
+            .with_context(|| "Failed to write document")?;
+        current_bytes += doc_len;
+        current_docs += 1;
+    }
 
-    let mut output = output
-        .iter()
-        .map(|file| BufWriter::new(file))
-        .collect::<Vec<BufWriter<&File>>>();
+    if let Some(mut w) = current.take() {
+        w.flush().with_context(|| "Failed to flush")?;
+    }
+    Ok(paths)
+}
+
+/// Expands any glob patterns in the input list, preserving argument order. A
+/// segment with no glob metacharacters is kept verbatim.
+fn expand_inputs(inputs: &[std::path::PathBuf]) -> anyhow::Result<Vec<std::path::PathBuf>> {
+    let mut resolved = Vec::new();
+    for input in inputs {
+        let pattern = input.to_str().ok_or_else(|| {
+            anyhow::Error::msg(format!("Invalid UTF-8 in input path: {:?}", input))
+        })?;
+        if pattern.contains(['*', '?', '[']) {
+            for entry in glob::glob(pattern).with_context(|| "Invalid glob pattern")? {
+                resolved.push(entry.with_context(|| "Failed to read glob entry")?);
+            }
+        } else {
+            resolved.push(input.clone());
+        }
+    }
+    Ok(resolved)
+}
+
+/// Streams every top-level document from each input file, in order, into a
+/// single writer, batching flushes the same way the split path does.
+fn merge(opt: &MergeOpts) -> anyhow::Result<()> {
+    let inputs = expand_inputs(&opt.inputs)?;
+
+    let out: Box<dyn Write> = match &opt.output {
+        Some(path) => {
+            Box::new(File::create(path).with_context(|| "Failed to create output file")?)
+        }
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let mut out = BufWriter::new(out);
 
-    let mut cycle = (0..output.len()).cycle();
     let mut writes: i64 = 0;
+    for path in inputs {
+        let f = File::open(&path).with_context(|| "Failed to open input file")?;
+        let mut f = BufReader::new(f);
+
+        loop {
+            if let Some(doc) = process_doc(Document::from_reader(&mut f))? {
+                doc.to_writer(&mut out)
+                    .with_context(|| "Failed to write document")?;
+                writes += 1;
 
+                if writes % AUTO_FLUSH == 0 {
+                    out.flush().with_context(|| "Failed to flush")?;
+                }
+            } else {
+                break;
+            }
+        }
+    }
+
+    out.flush().with_context(|| "Failed to flush")?;
+    Ok(())
+}
+
+/// Streams every document straight to stdout as a single BSON stream, batching
+/// flushes like the file-writing paths.
+fn split_to_stdout<R: Read>(mut f: R) -> anyhow::Result<()> {
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    let mut writes: i64 = 0;
     loop {
         if let Some(doc) = process_doc(Document::from_reader(&mut f))? {
-            let file_index = cycle.next().unwrap();
-            let mut buf = &mut output[file_index];
-            doc.to_writer(&mut buf)
+            doc.to_writer(&mut out)
                 .with_context(|| "Failed to write document")?;
             writes += 1;
 
             if writes % AUTO_FLUSH == 0 {
-                flush_all(&mut output)?;
+                out.flush().with_context(|| "Failed to flush")?;
             }
         } else {
             break;
         }
     }
 
-    flush_all(&mut output)?;
+    out.flush().with_context(|| "Failed to flush")?;
+    Ok(())
+}
+
+fn run_split(opt: &SplitOpts) -> anyhow::Result<()> {
+    validate(opt);
+
+    // `-` or an absent path means read the BSON stream from stdin.
+    let from_stdin = opt
+        .path
+        .as_ref()
+        .map_or(true, |p| p.as_os_str() == "-");
+
+    let reader: Box<dyn Read> = if from_stdin {
+        Box::new(BufReader::new(std::io::stdin().lock()))
+    } else {
+        let path = opt.path.as_ref().unwrap();
+        Box::new(BufReader::new(
+            File::open(path).with_context(|| "Failed to open file")?,
+        ))
+    };
+
+    if opt.stdout {
+        return split_to_stdout(reader);
+    }
+
+    // An explicit --prefix wins; otherwise fall back to the input file stem,
+    // which is unavailable when reading from stdin.
+    let prefix = match &opt.prefix {
+        Some(prefix) => prefix.clone(),
+        None => opt
+            .path
+            .as_ref()
+            .filter(|p| p.as_os_str() != "-")
+            .ok_or_else(|| anyhow::Error::msg("--prefix is required when reading from stdin"))?
+            .file_stem()
+            .ok_or_else(|| anyhow::Error::msg("Unable to extract prefix"))?
+            .to_str()
+            .ok_or_else(|| anyhow::Error::msg("Unable to read file path"))?
+            .to_owned(),
+    };
+    let prefix = prefix.as_str();
+    let f = reader;
+
+    let rotating = opt.max_size_mb.is_some() || opt.max_docs.is_some();
+    let paths = if rotating {
+        let max_bytes = opt.max_size_mb.map(|mb| mb * 1024 * 1024);
+        split_rotating(f, prefix, opt.split, max_bytes, opt.max_docs)?
+    } else {
+        // Round-robin mode requires an explicit file count (enforced by validate).
+        split_round_robin(
+            f,
+            prefix,
+            opt.split.unwrap(),
+            opt.shard_by.as_deref(),
+            opt.fallback_bucket,
+        )?
+    };
+
     paths.iter().for_each(|p| println!("{}", p));
 
     Ok(())
 }
+
+fn main() -> anyhow::Result<()> {
+    match Cli::from_args() {
+        Cli::Split(opt) => run_split(&opt),
+        Cli::Merge(opt) => merge(&opt),
+    }
+}